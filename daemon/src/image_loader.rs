@@ -1,13 +1,74 @@
-use std::{collections::HashMap, path::PathBuf, thread::JoinHandle};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+};
 
 use image::{open, RgbaImage};
 use log::warn;
 use smithay_client_toolkit::reexports::calloop::ping::Ping;
 
-struct Image {
-    data: Option<RgbaImage>,
-    thread_handle: Option<JoinHandle<Option<RgbaImage>>>,
+/// How many bytes of decoded `RgbaImage` data the cache is allowed to hold
+/// before it starts evicting the least-recently-used entries.
+const DEFAULT_CACHE_BUDGET_BYTES: u64 = 512 * 1024 * 1024;
+
+/// A request sent to a decode worker. The worker echoes `generation` back in
+/// its reply, which lets `ImageLoader::drain_replies` discard a stale reply
+/// if the path has been requested again (and the cache entry superseded)
+/// before the first decode finished.
+struct DecodeRequest {
+    path: PathBuf,
+    generation: u64,
+}
+
+/// Requests waiting to be picked up by a worker, split by priority so a
+/// surface actively waiting on an image is never stuck behind a prefetch for
+/// a wallpaper nobody is looking at yet.
+#[derive(Default)]
+struct RequestQueue {
+    wanted: VecDeque<DecodeRequest>,
+    prefetch: VecDeque<DecodeRequest>,
+    /// Set once the `ImageLoader` is dropped, so idle workers can exit
+    /// instead of blocking on the condvar forever.
+    shut_down: bool,
+}
+
+impl RequestQueue {
+    fn pop(&mut self) -> Option<DecodeRequest> {
+        self.wanted.pop_front().or_else(|| self.prefetch.pop_front())
+    }
+}
+
+struct DecodeReply {
+    path: PathBuf,
+    generation: u64,
+    result: Option<RgbaImage>,
+}
+
+/// An in-flight or completed decode, tracked so that multiple surfaces
+/// requesting the same path coalesce onto a single decode instead of each
+/// spawning their own.
+struct PendingDecode {
+    generation: u64,
     requesters: Vec<String>,
+    /// Whether this decode is in the `wanted` queue. A prefetch starts out
+    /// `false`; if a real `background_load` comes in for the same path
+    /// before the decode finishes, it's promoted so a surface actively
+    /// waiting on an image is never stuck behind a prefetch for a wallpaper
+    /// nobody is looking at yet.
+    wanted: bool,
+}
+
+/// A decoded image sitting in the cache, ordered by `last_used` for LRU
+/// eviction.
+struct CacheEntry {
+    data: RgbaImage,
+    bytes: u64,
+    last_used: u64,
 }
 
 pub enum ImageLoaderStatus {
@@ -16,108 +77,261 @@ pub enum ImageLoaderStatus {
     Error,
 }
 
+/// Decodes images off the calloop thread on a small pool of long-lived
+/// worker threads, instead of spawning a throwaway thread per request, and
+/// keeps decoded images around in a bounded LRU cache so that rotating
+/// through the same set of wallpapers (e.g. across several outputs) doesn't
+/// re-read and re-decode them from disk every time.
 pub struct ImageLoader {
-    images: HashMap<PathBuf, Image>,
-    ping: Ping,
+    cache: HashMap<PathBuf, CacheEntry>,
+    cache_bytes: u64,
+    cache_budget_bytes: u64,
+    /// Monotonic counter used both as the LRU clock and to tag requests so
+    /// stale replies can be recognised and dropped.
+    clock: u64,
+    pending: HashMap<PathBuf, PendingDecode>,
+    /// Paths whose decode failed. Consumed (and cleared) the next time
+    /// they're requested via `background_load`, so a permanently-bad path
+    /// surfaces `ImageLoaderStatus::Error` exactly once instead of being
+    /// silently re-decoded on every frame.
+    failed: HashSet<PathBuf>,
+    queue: Arc<(Mutex<RequestQueue>, Condvar)>,
+    reply_rx: Receiver<DecodeReply>,
+    _workers: Vec<JoinHandle<()>>,
+}
+
+impl Drop for ImageLoader {
+    fn drop(&mut self) {
+        let (queue, condvar) = &*self.queue;
+        queue.lock().unwrap().shut_down = true;
+        condvar.notify_all();
+    }
 }
 
 impl ImageLoader {
     pub fn new(ping: Ping) -> Self {
-        Self {
-            images: HashMap::new(),
-            ping,
-        }
+        Self::with_cache_budget(ping, DEFAULT_CACHE_BUDGET_BYTES)
     }
 
-    pub fn background_load(&mut self, path: PathBuf, requester_name: String) -> ImageLoaderStatus {
-        if let Some(image) = self.images.get_mut(&path) {
-            if let Some(handle) = image.thread_handle.take() {
-                if handle.is_finished() {
-                    match handle.join() {
-                        Ok(thread_result) => match thread_result {
-                            Some(image_data) => {
-                                image.data = Some(image_data);
-                            }
-                            None => {
-                                self.images.remove(&path);
-                                return ImageLoaderStatus::Error;
-                            }
-                        },
+    pub fn with_cache_budget(ping: Ping, cache_budget_bytes: u64) -> Self {
+        let queue = Arc::new((Mutex::new(RequestQueue::default()), Condvar::new()));
+        let (reply_tx, reply_rx) = mpsc::channel::<DecodeReply>();
+
+        let worker_count = std::thread::available_parallelism()
+            .map(|n| n.get().min(4))
+            .unwrap_or(1);
+        let workers = (0..worker_count)
+            .map(|_| {
+                let queue = queue.clone();
+                let reply_tx = reply_tx.clone();
+                let ping = ping.clone();
+                std::thread::spawn(move || loop {
+                    let (lock, condvar) = &*queue;
+                    let mut guard = lock.lock().unwrap();
+                    let request = loop {
+                        if let Some(request) = guard.pop() {
+                            break request;
+                        }
+                        if guard.shut_down {
+                            return;
+                        }
+                        guard = condvar.wait(guard).unwrap();
+                    };
+                    drop(guard);
+
+                    let result = match open(&request.path) {
+                        Ok(image) => Some(image.into_rgba8()),
                         Err(err) => {
                             warn!("{err:?}");
-                            self.images.remove(&path);
-                            return ImageLoaderStatus::Error;
+                            None
                         }
+                    };
+
+                    let reply = DecodeReply {
+                        path: request.path,
+                        generation: request.generation,
+                        result,
+                    };
+                    if reply_tx.send(reply).is_ok() {
+                        // Notify the event loop that an image has been decoded.
+                        // We need this so that Surface::draw is called even if
+                        // wl_surface::frame doesn't get called by the
+                        // compositor (e.g. a window is fullscreen). Do the
+                        // send first, then the ping, otherwise we'd have a
+                        // race condition.
+                        ping.ping();
                     }
-                } else {
-                    // the thread is still running
-                    // reassign the handle
-                    image.thread_handle = Some(handle);
-                    return ImageLoaderStatus::Waiting;
-                }
+                })
+            })
+            .collect();
+
+        Self {
+            cache: HashMap::new(),
+            cache_bytes: 0,
+            cache_budget_bytes,
+            clock: 0,
+            pending: HashMap::new(),
+            failed: HashSet::new(),
+            queue,
+            reply_rx,
+            _workers: workers,
+        }
+    }
+
+    pub fn background_load(&mut self, path: PathBuf, requester_name: String) -> ImageLoaderStatus {
+        self.drain_replies();
+
+        if let Some(entry) = self.cache.get_mut(&path) {
+            self.clock += 1;
+            entry.last_used = self.clock;
+            return ImageLoaderStatus::Loaded(entry.data.clone());
+        }
+
+        if let Some(pending) = self.pending.get_mut(&path) {
+            if !pending.requesters.iter().any(|name| name == &requester_name) {
+                pending.requesters.push(requester_name);
             }
-            if let Some(data) = &image.data {
-                // If the requesters is only one and it's the same as the current
-                if image.requesters.len() == 1
-                    && image.requesters.first().unwrap() == &requester_name
-                {
-                    // Just send it up and remove it from the map
-                    let image = self.images.remove(&path);
-                    ImageLoaderStatus::Loaded(image.unwrap().data.unwrap())
-                } else {
-                    // otherwise this image has been requested by multiple surfaces
-                    let requesters = &mut image.requesters;
-                    if let Some(index) = requesters.iter().position(|name| name == &requester_name)
-                    {
-                        requesters.remove(index);
-                    }
-                    ImageLoaderStatus::Loaded(data.clone())
-                }
-            } else {
-                // The decoded image is not ready yet
-                ImageLoaderStatus::Waiting
+            if !pending.wanted {
+                pending.wanted = true;
+                self.promote_to_wanted(&path);
             }
+            return ImageLoaderStatus::Waiting;
+        }
+
+        if self.failed.remove(&path) {
+            return ImageLoaderStatus::Error;
+        }
+
+        self.enqueue(path, vec![requester_name], true);
+        ImageLoaderStatus::Waiting
+    }
+
+    /// Kick off decoding `path` ahead of it actually being displayed, e.g.
+    /// called with `ImagePicker::peek_next()` so a timed rotation doesn't
+    /// have to stall on disk I/O when the timer fires. A no-op if the image
+    /// is already cached or already being decoded.
+    pub fn prefetch(&mut self, path: PathBuf) {
+        self.drain_replies();
+
+        if self.cache.contains_key(&path)
+            || self.pending.contains_key(&path)
+            || self.failed.contains(&path)
+        {
+            return;
+        }
+
+        self.enqueue(path, Vec::new(), false);
+    }
+
+    /// Moves an already-queued prefetch request for `path` to the front of
+    /// the `wanted` queue, so a surface that actually needs the image right
+    /// now isn't stuck behind every other prefetch ahead of it.
+    fn promote_to_wanted(&mut self, path: &Path) {
+        let (lock, condvar) = &*self.queue;
+        let mut guard = lock.lock().unwrap();
+        if let Some(index) = guard.prefetch.iter().position(|request| request.path == path) {
+            let request = guard.prefetch.remove(index).unwrap();
+            guard.wanted.push_front(request);
+        }
+        drop(guard);
+        condvar.notify_one();
+    }
+
+    fn enqueue(&mut self, path: PathBuf, requesters: Vec<String>, wanted: bool) {
+        self.clock += 1;
+        let generation = self.clock;
+        self.pending.insert(
+            path.clone(),
+            PendingDecode {
+                generation,
+                requesters,
+                wanted,
+            },
+        );
+        let request = DecodeRequest {
+            path,
+            generation,
+        };
+
+        let (lock, condvar) = &*self.queue;
+        let mut guard = lock.lock().unwrap();
+        if wanted {
+            guard.wanted.push_back(request);
         } else {
-            self.start_new_thread(path, requester_name);
-            ImageLoaderStatus::Waiting
+            guard.prefetch.push_back(request);
         }
+        drop(guard);
+        condvar.notify_one();
     }
 
-    fn start_new_thread(&mut self, path: PathBuf, requester_name: String) {
-        // Start loading a new image in a new thread
-        let path_clone = path.clone();
-        let ping_clone = self.ping.clone();
-        let handle = std::thread::spawn(move || match open(&path_clone) {
-            Ok(image) => {
-                // Notify the event loop that the image has been loaded
-                // We need this so that Surface::load_wallpaper is called even if
-                // wl_surface::frame doesn't get called by the compositor (e.g. a window is
-                // fullscreen)
-                // Do the conversion first, then the ping, otherwise we will have a race
-                // condition
-                let image = image.into_rgba8();
-                ping_clone.ping();
-                Some(image)
+    /// Pulls every reply a worker has produced since the last call and
+    /// folds it into the cache, discarding replies for paths that were
+    /// re-requested (and thus superseded) while the decode was in flight.
+    fn drain_replies(&mut self) {
+        while let Ok(reply) = self.reply_rx.try_recv() {
+            let Some(pending) = self.pending.get(&reply.path) else {
+                continue;
+            };
+            if pending.generation != reply.generation {
+                // A newer request for the same path is already in flight.
+                continue;
             }
-            Err(err) => {
-                warn!("{err:?}");
-                None
+            let pending = self.pending.remove(&reply.path).unwrap();
+
+            match reply.result {
+                Some(data) => {
+                    self.clock += 1;
+                    let bytes = data.width() as u64 * data.height() as u64 * 4;
+                    self.insert_into_cache(reply.path, data, bytes, self.clock);
+                }
+                None => {
+                    // Remember the failure so the next `background_load`
+                    // for this path surfaces ImageLoaderStatus::Error
+                    // instead of silently enqueuing the same bad decode
+                    // again.
+                    let _ = pending;
+                    self.failed.insert(reply.path);
+                }
             }
-        });
-        let image = Image {
-            requesters: vec![requester_name],
-            thread_handle: Some(handle),
-            data: None,
-        };
-        self.images.insert(path, image);
+        }
+    }
+
+    fn insert_into_cache(&mut self, path: PathBuf, data: RgbaImage, bytes: u64, last_used: u64) {
+        self.cache_bytes += bytes;
+        self.cache.insert(
+            path,
+            CacheEntry {
+                data,
+                bytes,
+                last_used,
+            },
+        );
+        self.evict_if_over_budget();
+    }
+
+    fn evict_if_over_budget(&mut self) {
+        while self.cache_bytes > self.cache_budget_bytes {
+            let Some(lru_path) = self
+                .cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = self.cache.remove(&lru_path) {
+                self.cache_bytes = self.cache_bytes.saturating_sub(entry.bytes);
+            }
+        }
     }
 
-    /// Check that there are no threads waiting on zero requesters
+    /// Check that no path is simultaneously tracked as in-flight and as
+    /// already cached.
     #[cfg(debug_assertions)]
     pub fn check_lingering_threads(&mut self) {
         debug_assert!(!self
-            .images
-            .iter()
-            .any(|(_, image)| { image.requesters.is_empty() }));
+            .pending
+            .keys()
+            .any(|path| self.cache.contains_key(path)));
     }
 }
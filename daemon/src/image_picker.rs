@@ -0,0 +1,140 @@
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+use color_eyre::Result;
+use image::DynamicImage;
+use log::warn;
+use smithay_client_toolkit::reexports::calloop::ping;
+
+use crate::filelist_cache::FilelistCache;
+use crate::image_loader::{ImageLoader, ImageLoaderStatus};
+use crate::wallpaper_info::WallpaperInfo;
+
+/// Picks which image a `Surface` should currently display, and drives the
+/// background `ImageLoader` that decodes it: the current path is requested
+/// with `background_load`, while the path that rotation would move to next
+/// is opportunistically handed to `prefetch` so the timer firing doesn't
+/// have to stall on disk I/O.
+pub struct ImagePicker {
+    filelist_cache: Rc<RefCell<FilelistCache>>,
+    image_loader: ImageLoader,
+    paths: Vec<PathBuf>,
+    current: usize,
+    /// The path last returned by `get_image`, i.e. what's currently on
+    /// screen. Lets `get_image` tell "rotation moved to a new path" apart
+    /// from "still the same path, which the LRU cache keeps happily
+    /// returning" so it only hands back `Some` (and `Surface::draw` only
+    /// reloads the texture) on an actual change.
+    displayed: Option<PathBuf>,
+    pub image_changed_instant: Instant,
+    _ping_source: ping::PingSource,
+}
+
+impl ImagePicker {
+    pub fn new(
+        wallpaper_info: Arc<WallpaperInfo>,
+        filelist_cache: Rc<RefCell<FilelistCache>>,
+    ) -> Self {
+        let (ping, ping_source) = ping::make_ping().expect("failed to create ping source");
+        let paths = filelist_cache.borrow_mut().get(&wallpaper_info.path);
+
+        Self {
+            filelist_cache,
+            image_loader: ImageLoader::new(ping),
+            paths,
+            current: 0,
+            displayed: None,
+            image_changed_instant: Instant::now(),
+            _ping_source: ping_source,
+        }
+    }
+
+    /// The path that should be on screen right now.
+    pub fn current_image(&self) -> &PathBuf {
+        &self.paths[self.current]
+    }
+
+    /// The path rotation would move to next, without actually moving there.
+    /// Used to prefetch the upcoming wallpaper ahead of the rotation timer
+    /// firing.
+    pub fn peek_next(&self) -> Option<&PathBuf> {
+        if self.paths.is_empty() {
+            return None;
+        }
+        self.paths.get((self.current + 1) % self.paths.len())
+    }
+
+    /// Moves rotation to the next path. The new image isn't decoded here;
+    /// the next `get_image` call picks it up (from cache, if it was
+    /// prefetched in time).
+    pub fn next_image(&mut self) {
+        if self.paths.is_empty() {
+            return;
+        }
+        self.current = (self.current + 1) % self.paths.len();
+        self.image_changed_instant = Instant::now();
+    }
+
+    /// Returns the current image once it's finished decoding, prefetching
+    /// the next one in the background so a future rotation doesn't have to
+    /// wait on disk I/O. Returns `None` once `current_image()` has already
+    /// been handed back by a previous call, so `Surface::draw` only reloads
+    /// the texture (and restarts the transition) when rotation actually
+    /// moved to a new path, instead of every frame the cache keeps serving
+    /// the same path back.
+    pub fn get_image(&mut self) -> Result<Option<DynamicImage>> {
+        if self.paths.is_empty() {
+            return Ok(None);
+        }
+
+        let current = self.current_image().clone();
+        if let Some(next) = self.peek_next().cloned() {
+            if next != current {
+                self.image_loader.prefetch(next);
+            }
+        }
+
+        if self.displayed.as_ref() == Some(&current) {
+            return Ok(None);
+        }
+
+        match self.image_loader.background_load(current.clone(), "surface".to_string()) {
+            ImageLoaderStatus::Loaded(data) => {
+                self.displayed = Some(current);
+                Ok(Some(DynamicImage::ImageRgba8(data)))
+            }
+            ImageLoaderStatus::Waiting => Ok(None),
+            ImageLoaderStatus::Error => {
+                warn!("failed to decode {current:?}, skipping it");
+                self.next_image();
+                Ok(None)
+            }
+        }
+    }
+
+    /// Updates the set of candidate paths after the config changed. Returns
+    /// true if the resolved path changed, so the caller knows to redraw
+    /// even when no rotation timer fired.
+    ///
+    /// Preserves rotation's current position when the path it was showing
+    /// is still in the new path set (e.g. a duration-only edit), instead of
+    /// snapping back to the first image on every reload.
+    pub fn update(&mut self, wallpaper_info: &WallpaperInfo) -> bool {
+        let previous = self.paths.get(self.current).cloned();
+
+        self.paths = self.filelist_cache.borrow_mut().get(&wallpaper_info.path);
+        self.current = previous
+            .as_ref()
+            .and_then(|path| self.paths.iter().position(|candidate| candidate == path))
+            .unwrap_or(0);
+
+        let changed = previous.as_ref() != self.paths.get(self.current);
+        if changed {
+            self.image_changed_instant = Instant::now();
+        }
+        changed
+    }
+}
@@ -0,0 +1,477 @@
+use std::ffi::c_void;
+use std::time::Duration;
+
+use color_eyre::Result;
+use gl::types::{GLenum, GLint, GLuint};
+use image::RgbaImage;
+use smithay_client_toolkit::reexports::client::protocol::wl_surface;
+use wayland_egl::WlEglSurface;
+
+use crate::transition::{Transition, TransitionKind};
+use crate::wallpaper_info::BackgroundMode;
+
+const VERTEX_SHADER: &str = include_str!("shaders/quad.vert");
+const FADE_SHADER: &str = include_str!("shaders/fade.frag");
+const WIPE_SHADER: &str = include_str!("shaders/wipe.frag");
+const GROW_SHADER: &str = include_str!("shaders/grow.frag");
+
+/// An EGL call failed. `ContextLost` and `BadSurface` are recoverable by
+/// tearing down and rebuilding the `EglContext` (see
+/// `Surface::recover_from_context_loss`); anything else is a genuine
+/// failure that should be propagated.
+#[derive(Debug)]
+pub enum EglError {
+    ContextLost,
+    BadSurface,
+    Other(egl::Error),
+}
+
+impl std::fmt::Display for EglError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ContextLost => write!(f, "EGL context lost"),
+            Self::BadSurface => write!(f, "EGL surface is no longer valid"),
+            Self::Other(err) => write!(f, "EGL call failed: {err:?}"),
+        }
+    }
+}
+
+impl std::error::Error for EglError {}
+
+impl EglError {
+    fn from_egl(err: egl::Error) -> Self {
+        match err {
+            egl::Error::ContextLost => Self::ContextLost,
+            egl::Error::BadSurface => Self::BadSurface,
+            err => Self::Other(err),
+        }
+    }
+
+    /// Whether this error indicates the `EglContext` itself is no longer
+    /// usable and must be rebuilt, rather than a one-off failure.
+    pub fn is_context_loss(&self) -> bool {
+        matches!(self, Self::ContextLost | Self::BadSurface)
+    }
+}
+
+pub struct EglContext {
+    pub display: egl::Display,
+    context: egl::Context,
+    config: egl::Config,
+    surface: egl::Surface,
+    // Must outlive `surface`, which borrows from the native window it wraps.
+    _wl_egl_surface: WlEglSurface,
+}
+
+impl EglContext {
+    pub fn new(display: egl::Display, surface: &wl_surface::WlSurface) -> Self {
+        let attributes = [
+            egl::RED_SIZE,
+            8,
+            egl::GREEN_SIZE,
+            8,
+            egl::BLUE_SIZE,
+            8,
+            egl::ALPHA_SIZE,
+            8,
+            egl::SURFACE_TYPE,
+            egl::WINDOW_BIT,
+            egl::RENDERABLE_TYPE,
+            egl::OPENGL_ES2_BIT,
+            egl::NONE,
+        ];
+        let config = egl::API
+            .choose_first_config(display, &attributes)
+            .expect("failed to select EGL config")
+            .expect("no EGL config matching the requested attributes");
+
+        let context_attributes = [egl::CONTEXT_CLIENT_VERSION, 2, egl::NONE];
+        let context = egl::API
+            .create_context(display, config, None, &context_attributes)
+            .expect("failed to create EGL context");
+
+        let wl_egl_surface =
+            WlEglSurface::new(surface.id(), 1, 1).expect("failed to create wl_egl_surface");
+        let egl_surface = unsafe {
+            egl::API
+                .create_window_surface(
+                    display,
+                    config,
+                    wl_egl_surface.ptr() as egl::NativeWindowType,
+                    None,
+                )
+                .expect("failed to create EGL window surface")
+        };
+
+        Self {
+            display,
+            context,
+            config,
+            surface: egl_surface,
+            _wl_egl_surface: wl_egl_surface,
+        }
+    }
+
+    pub fn make_current(&self) -> Result<(), EglError> {
+        egl::API
+            .make_current(
+                self.display,
+                Some(self.surface),
+                Some(self.surface),
+                Some(self.context),
+            )
+            .map_err(EglError::from_egl)
+    }
+
+    pub fn swap_buffers(&self) -> Result<(), EglError> {
+        egl::API
+            .swap_buffers(self.display, self.surface)
+            .map_err(EglError::from_egl)
+    }
+
+    /// Blocks until the GL commands issued against this context have been
+    /// executed by the driver. Used before deleting textures on resize, so
+    /// the old-sized buffers aren't freed while the GPU might still be
+    /// reading from them.
+    pub fn wait_gl(&self) -> Result<(), EglError> {
+        egl::API.wait_gl().map_err(EglError::from_egl)
+    }
+
+    pub fn resize(&self, surface: &wl_surface::WlSurface, width: i32, height: i32) {
+        let _ = surface;
+        self._wl_egl_surface.resize(width, height, 0, 0);
+        unsafe { gl::Viewport(0, 0, width, height) };
+    }
+}
+
+impl Drop for EglContext {
+    fn drop(&mut self) {
+        // Context-loss recovery replaces `EglContext` wholesale (see
+        // `Surface::recover_from_context_loss`); without this the old
+        // surface and context otherwise leak, since EGL doesn't free them
+        // on its own.
+        let _ = egl::API.destroy_surface(self.display, self.surface);
+        let _ = egl::API.destroy_context(self.display, self.context);
+    }
+}
+
+struct Texture {
+    id: GLuint,
+}
+
+impl Texture {
+    fn new() -> Self {
+        let mut id = 0;
+        unsafe {
+            gl::GenTextures(1, &mut id);
+            gl::BindTexture(gl::TEXTURE_2D, id);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+            gl::TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_WRAP_S,
+                gl::CLAMP_TO_EDGE as GLint,
+            );
+            gl::TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_WRAP_T,
+                gl::CLAMP_TO_EDGE as GLint,
+            );
+        }
+        Self { id }
+    }
+
+    fn upload(&self, image: &RgbaImage) {
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.id);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA as GLint,
+                image.width() as GLint,
+                image.height() as GLint,
+                0,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                image.as_raw().as_ptr() as *const c_void,
+            );
+        }
+    }
+
+    /// Allocates `width` x `height` of undefined storage with no source
+    /// data, so the texture can be used as a framebuffer color attachment.
+    fn new_render_target(width: i32, height: i32) -> Self {
+        let texture = Self::new();
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, texture.id);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA as GLint,
+                width,
+                height,
+                0,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                std::ptr::null(),
+            );
+        }
+        texture
+    }
+
+    fn bind(&self, unit: u32) {
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0 + unit);
+            gl::BindTexture(gl::TEXTURE_2D, self.id);
+        }
+    }
+
+    fn delete(&mut self) {
+        unsafe { gl::DeleteTextures(1, &self.id) };
+        self.id = 0;
+    }
+}
+
+struct ShaderProgram {
+    id: GLuint,
+    u_progress: GLint,
+    u_aspect: GLint,
+}
+
+impl ShaderProgram {
+    fn new(fragment_source: &str) -> Self {
+        let vertex = compile_shader(gl::VERTEX_SHADER, VERTEX_SHADER);
+        let fragment = compile_shader(gl::FRAGMENT_SHADER, fragment_source);
+        let id = unsafe {
+            let program = gl::CreateProgram();
+            gl::AttachShader(program, vertex);
+            gl::AttachShader(program, fragment);
+            gl::LinkProgram(program);
+            gl::DeleteShader(vertex);
+            gl::DeleteShader(fragment);
+            program
+        };
+
+        let u_progress = unsafe { gl::GetUniformLocation(id, c"u_progress".as_ptr().cast()) };
+        let u_aspect = unsafe { gl::GetUniformLocation(id, c"u_aspect".as_ptr().cast()) };
+
+        Self {
+            id,
+            u_progress,
+            u_aspect,
+        }
+    }
+
+    fn use_program(&self, progress: f32, aspect: f32) {
+        unsafe {
+            gl::UseProgram(self.id);
+            gl::Uniform1f(self.u_progress, progress);
+            if self.u_aspect >= 0 {
+                gl::Uniform1f(self.u_aspect, aspect);
+            }
+            let u_previous = gl::GetUniformLocation(self.id, c"u_previous".as_ptr().cast());
+            let u_current = gl::GetUniformLocation(self.id, c"u_current".as_ptr().cast());
+            gl::Uniform1i(u_previous, 0);
+            gl::Uniform1i(u_current, 1);
+        }
+    }
+}
+
+fn compile_shader(kind: GLenum, source: &str) -> GLuint {
+    unsafe {
+        let shader = gl::CreateShader(kind);
+        let source_ptr = source.as_ptr() as *const i8;
+        let len = source.len() as GLint;
+        gl::ShaderSource(shader, 1, &source_ptr, &len);
+        gl::CompileShader(shader);
+        shader
+    }
+}
+
+/// Renders the wallpaper, cross-fading from whatever was previously on
+/// screen to the most recently loaded image over `transition.duration()`,
+/// using `transition.kind` as the visual effect and `transition.easing` to
+/// shape how `progress` moves from `0.0` to `1.0`.
+pub struct Renderer {
+    previous: Texture,
+    current: Texture,
+    fade: ShaderProgram,
+    wipe: ShaderProgram,
+    grow: ShaderProgram,
+    mode: BackgroundMode,
+    transition: Transition,
+    time_started: u32,
+    width: i32,
+    height: i32,
+    /// The image most recently uploaded to `current`, kept around so it can
+    /// be re-uploaded after the GL textures are deleted and recreated (on
+    /// resize, or after rebuilding the `EglContext` following context loss).
+    last_image: RgbaImage,
+}
+
+impl Renderer {
+    /// # Safety
+    /// Must be called with a current EGL context.
+    pub unsafe fn new(image: RgbaImage) -> Result<Self> {
+        let current = Texture::new();
+        current.upload(&image);
+        let previous = Texture::new();
+        previous.upload(&image);
+
+        Ok(Self {
+            previous,
+            current,
+            fade: ShaderProgram::new(FADE_SHADER),
+            wipe: ShaderProgram::new(WIPE_SHADER),
+            grow: ShaderProgram::new(GROW_SHADER),
+            mode: BackgroundMode::default(),
+            transition: Transition::default(),
+            // A fresh renderer never has anything to transition away from.
+            time_started: 0,
+            width: 1,
+            height: 1,
+            last_image: image,
+        })
+    }
+
+    /// Snapshots whatever is currently being displayed (even mid-transition)
+    /// into `previous`, uploads `image` as the new `current`, and restarts
+    /// the transition from `time`. Doing the snapshot first means a new
+    /// image arriving before the previous transition finished never causes
+    /// a visible jump: the shader keeps blending from exactly what was on
+    /// screen the instant the new image arrived.
+    pub fn load_texture(
+        &mut self,
+        image: RgbaImage,
+        mode: BackgroundMode,
+        transition: Transition,
+        time: u32,
+    ) -> Result<()> {
+        if self.width > 0 && self.height > 0 && self.is_drawing_animation(time) {
+            // Mid-transition: `current` alone is not what's on screen, the
+            // blend of `previous` and `current` at the current progress is.
+            // Bake that blend into an offscreen texture and swap it in as
+            // `previous`, instead of swapping in the raw `current` texture.
+            self.snapshot_into_previous(time);
+        } else {
+            std::mem::swap(&mut self.previous, &mut self.current);
+        }
+        self.current.upload(&image);
+        self.mode = mode;
+        self.transition = transition;
+        self.time_started = time;
+        self.last_image = image;
+
+        Ok(())
+    }
+
+    /// Renders the blend of `previous`/`current` at `time`'s progress into a
+    /// fresh offscreen texture and swaps it into `self.previous`, deleting
+    /// the texture object `previous` held before (it's now fully replaced,
+    /// and would otherwise leak).
+    fn snapshot_into_previous(&mut self, time: u32) {
+        let mut snapshot = Texture::new_render_target(self.width, self.height);
+
+        unsafe {
+            let mut fbo = 0;
+            gl::GenFramebuffers(1, &mut fbo);
+            let mut previous_fbo_binding = 0;
+            gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut previous_fbo_binding);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+            gl::FramebufferTexture2D(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                snapshot.id,
+                0,
+            );
+            gl::Viewport(0, 0, self.width, self.height);
+
+            let progress = self.progress(time);
+            let aspect = self.width as f32 / self.height.max(1) as f32;
+            let program = match self.transition.kind {
+                TransitionKind::Fade => &self.fade,
+                TransitionKind::Wipe => &self.wipe,
+                TransitionKind::Grow => &self.grow,
+            };
+            program.use_program(progress, aspect);
+            self.previous.bind(0);
+            self.current.bind(1);
+            gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+
+            gl::BindFramebuffer(gl::FRAMEBUFFER, previous_fbo_binding as GLuint);
+            gl::DeleteFramebuffers(1, &fbo);
+        }
+
+        std::mem::swap(&mut self.previous, &mut snapshot);
+        snapshot.delete();
+    }
+
+    fn progress(&self, time: u32) -> f32 {
+        let elapsed = Duration::from_millis(time.saturating_sub(self.time_started) as u64);
+        self.transition.progress(elapsed)
+    }
+
+    pub fn is_drawing_animation(&self, time: u32) -> bool {
+        self.progress(time) < 1.0
+    }
+
+    /// # Safety
+    /// Must be called with a current EGL context.
+    pub unsafe fn draw(&mut self, time: u32) -> Result<()> {
+        let progress = self.progress(time);
+        let aspect = self.width as f32 / self.height.max(1) as f32;
+
+        let program = match self.transition.kind {
+            TransitionKind::Fade => &self.fade,
+            TransitionKind::Wipe => &self.wipe,
+            TransitionKind::Grow => &self.grow,
+        };
+        program.use_program(progress, aspect);
+
+        self.previous.bind(0);
+        self.current.bind(1);
+
+        gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+
+        Ok(())
+    }
+
+    pub fn clear_after_draw(&self) -> Result<()> {
+        unsafe { gl::BindTexture(gl::TEXTURE_2D, 0) };
+        Ok(())
+    }
+
+    pub fn resize(&mut self, width: i32, height: i32) -> Result<()> {
+        self.width = width;
+        self.height = height;
+        unsafe { gl::Viewport(0, 0, width, height) };
+        Ok(())
+    }
+
+    /// Deletes the GL texture objects backing `previous` and `current`.
+    /// Call this only while the old, pre-resize buffers are guaranteed to no
+    /// longer be in use (see `EglContext::wait_gl`); `reload_current_texture`
+    /// must be called afterwards, before the next `draw`, to get valid
+    /// texture objects back.
+    pub fn delete_textures(&mut self) {
+        self.previous.delete();
+        self.current.delete();
+    }
+
+    /// Recreates the GL texture objects and re-uploads `last_image` into
+    /// both `previous` and `current`, then marks the transition as finished.
+    /// Used after `delete_textures`, and after rebuilding the `EglContext`
+    /// following context loss (where the old texture objects are invalid
+    /// regardless of whether `delete_textures` was called).
+    pub fn reload_current_texture(&mut self) -> Result<()> {
+        self.previous = Texture::new();
+        self.previous.upload(&self.last_image);
+        self.current = Texture::new();
+        self.current.upload(&self.last_image);
+        // Nothing to transition from after a reload; show `current` as-is.
+        self.time_started = 0;
+
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@ use std::time::{Duration, Instant};
 use color_eyre::Result;
 use image::imageops::FilterType;
 use image::{DynamicImage, ImageBuffer, Pixel, Rgba};
+use log::warn;
 use smithay_client_toolkit::reexports::calloop::timer::{TimeoutAction, Timer};
 use smithay_client_toolkit::reexports::calloop::{LoopHandle, RegistrationToken};
 use smithay_client_toolkit::reexports::client::protocol::wl_output::WlOutput;
@@ -15,7 +16,7 @@ use smithay_client_toolkit::shell::wlr_layer::{LayerSurface, LayerSurfaceConfigu
 
 use crate::filelist_cache::FilelistCache;
 use crate::image_picker::ImagePicker;
-use crate::render::{EglContext, Renderer};
+use crate::render::{EglContext, EglError, Renderer};
 use crate::wallpaper_info::{BackgroundMode, WallpaperInfo};
 use crate::wpaperd::Wpaperd;
 
@@ -27,7 +28,12 @@ pub struct Surface {
     pub width: u32,
     pub height: u32,
     pub scale: i32,
-    egl_context: EglContext,
+    /// `None` only for the brief window inside `recover_from_context_loss`
+    /// between tearing down the old context and constructing its
+    /// replacement; an `Option` so that teardown can happen as its own step
+    /// instead of racing the construction of the new context (EGL disallows
+    /// two window surfaces on the same native window at once).
+    egl_context: Option<EglContext>,
     renderer: Renderer,
     pub image_picker: ImagePicker,
     pub event_source: Option<RegistrationToken>,
@@ -67,7 +73,7 @@ impl Surface {
             height: 0,
             scale: scale_factor,
             surface,
-            egl_context,
+            egl_context: Some(egl_context),
             renderer,
             image_picker,
             event_source: None,
@@ -75,6 +81,15 @@ impl Surface {
         }
     }
 
+    /// The active EGL context. Only absent for the brief window inside
+    /// `recover_from_context_loss` between tearing down the old context and
+    /// constructing its replacement.
+    fn egl_context(&self) -> &EglContext {
+        self.egl_context
+            .as_ref()
+            .expect("surface has no EGL context outside of recovery")
+    }
+
     /// Returns true if something has been drawn to the surface
     pub fn draw(&mut self, qh: &QueueHandle<Wpaperd>, time: u32) -> Result<()> {
         debug_assert!(self.width != 0 || self.height != 0);
@@ -82,20 +97,30 @@ impl Surface {
         let width = self.width as i32 * self.scale;
         let height = self.height as i32 * self.scale;
 
-        // Use the correct context before loading the texture and drawing
-        self.egl_context.make_current()?;
+        // Use the correct context before loading the texture and drawing.
+        // A compositor reconfigure or a GPU reset can invalidate the
+        // context between frames; rebuild it instead of propagating the
+        // error (and panicking further down in the caller) when that
+        // happens.
+        if let Err(err) = self.egl_context().make_current() {
+            self.recover_from_context_loss(err)?;
+        }
 
         if let Some(mut image) = self.image_picker.get_image()? {
             let image = image.into_rgba8();
-            self.renderer
-                .load_texture(image.into(), self.wallpaper_info.mode)?;
-            self.renderer.start_animation(time);
+            // `load_texture` snapshots whatever is currently being displayed
+            // (even mid-transition) into `previous` and restarts the
+            // transition from there, so a timer firing before the previous
+            // transition finished never produces a visual jump.
+            self.renderer.load_texture(
+                image.into(),
+                self.wallpaper_info.mode,
+                self.wallpaper_info.transition,
+                time,
+            )?;
 
             // self.apply_shadow(&mut image, width.try_into()?);
         }
-        if self.renderer.time_started == 0 {
-            self.renderer.start_animation(time);
-        }
 
         unsafe { self.renderer.draw(time)? };
 
@@ -104,11 +129,21 @@ impl Surface {
         }
 
         self.renderer.clear_after_draw()?;
-        self.egl_context.swap_buffers()?;
+        if let Err(err) = self.egl_context().swap_buffers() {
+            self.recover_from_context_loss(err)?;
+            // The rebuilt context's back buffer is undefined; re-render
+            // against it before retrying the swap, or we'd present a
+            // black/garbage frame.
+            unsafe { self.renderer.draw(time)? };
+            self.renderer.clear_after_draw()?;
+            // Retry the swap once against the rebuilt context; if this also
+            // fails we give up on this frame and let the next one retry.
+            self.egl_context().swap_buffers()?;
+        }
 
         // Reset the context
         egl::API
-            .make_current(self.egl_context.display, None, None, None)
+            .make_current(self.egl_context().display, None, None, None)
             .unwrap();
 
         // Mark the entire surface as damaged
@@ -120,6 +155,43 @@ impl Surface {
         Ok(())
     }
 
+    /// Rebuilds the EGL context and re-uploads the currently displayed
+    /// wallpaper. Compositor reconfigures, GPU resets and output changes can
+    /// invalidate the context (`EGL_CONTEXT_LOST` / `EGL_BAD_SURFACE`); when
+    /// that happens the old context is unusable and has to be torn down and
+    /// recreated rather than retried.
+    ///
+    /// Returns the original error if it isn't a context-loss condition, so
+    /// callers can still propagate genuine failures.
+    fn recover_from_context_loss(&mut self, err: EglError) -> Result<()> {
+        if !err.is_context_loss() {
+            return Err(err.into());
+        }
+
+        warn!(
+            "EGL context lost on surface {}, rebuilding: {err:?}",
+            self.name
+        );
+
+        let display = self.egl_context().display;
+        // Drop the old context/surface before constructing the new one.
+        // `EglContext::new` creates a new `WlEglSurface` on `self.surface`,
+        // and EGL disallows a native window having two associated window
+        // surfaces at once: building the new one first, and only dropping
+        // the old one when the assignment replaces it, used to hit
+        // `EGL_BAD_ALLOC` inside `EglContext::new` and panic.
+        self.egl_context.take();
+        self.egl_context = Some(EglContext::new(display, &self.surface));
+        self.egl_context().make_current()?;
+
+        let width = self.width as i32 * self.scale;
+        let height = self.height as i32 * self.scale;
+        self.renderer.resize(width, height)?;
+        self.renderer.reload_current_texture()?;
+
+        Ok(())
+    }
+
     fn apply_shadow(&self, image: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, width: u32) {
         if self.wallpaper_info.apply_shadow.unwrap_or_default() {
             const GRADIENT_HEIGHT: u32 = 11;
@@ -155,16 +227,31 @@ impl Surface {
 
     /// Resize the surface
     /// configure: None means that the scale factor has changed
-    pub fn resize(&mut self, configure: Option<LayerSurfaceConfigure>) {
+    pub fn resize(&mut self, configure: Option<LayerSurfaceConfigure>) -> Result<()> {
         if let Some(configure) = configure {
             (self.width, self.height) = configure.new_size;
         }
         let width = self.width as i32 * self.scale;
         let height = self.height as i32 * self.scale;
-        self.egl_context.resize(&self.surface, width, height);
+
+        if let Err(err) = self.egl_context().make_current() {
+            self.recover_from_context_loss(err)?;
+        }
+        // Make sure no in-flight GPU commands still reference the
+        // old-sized buffers before we resize the surface and delete the
+        // textures sized for it.
+        self.egl_context().wait_gl()?;
+        self.renderer.delete_textures();
+        self.renderer.reload_current_texture()?;
+
+        self.egl_context().resize(&self.surface, width, height);
         // Resize the gl viewport
-        self.egl_context.make_current().unwrap();
-        self.renderer.resize(width, height).unwrap();
+        if let Err(err) = self.egl_context().make_current() {
+            self.recover_from_context_loss(err)?;
+        }
+        self.renderer.resize(width, height)?;
+
+        Ok(())
     }
 
     /// Check that the dimensions are valid
@@ -184,13 +271,23 @@ impl Surface {
             // Put the new value in place
             std::mem::swap(&mut self.wallpaper_info, &mut wallpaper_info);
             let path_changed = self.image_picker.update(&*self.wallpaper_info);
-            if self.wallpaper_info.duration != wallpaper_info.duration {
+
+            // Joining or leaving a sync group changes who is responsible for
+            // driving this surface's rotation, independently of whether the
+            // duration itself changed: a grouped surface is advanced by
+            // Wpaperd's shared group timer instead of its own.
+            let joined_group = wallpaper_info.group.is_none() && self.sync_group().is_some();
+            let left_group = wallpaper_info.group.is_some() && self.sync_group().is_none();
+
+            if joined_group {
+                if let Some(registration_token) = self.event_source.take() {
+                    handle.remove(registration_token);
+                }
+            }
+
+            if self.wallpaper_info.duration != wallpaper_info.duration || left_group {
                 match (self.wallpaper_info.duration, wallpaper_info.duration) {
-                    (None, None) => {
-                        unreachable!()
-                    }
-                    // There was a duration before but now it has been removed
-                    (None, Some(_)) => {
+                    (None, _) => {
                         if let Some(registration_token) = self.event_source.take() {
                             handle.remove(registration_token);
                         }
@@ -198,39 +295,69 @@ impl Surface {
                             self.queue_draw(qh);
                         }
                     }
-                    // There wasn't a duration before but now it has been added or it has changed
-                    (Some(new_duration), None) | (Some(new_duration), Some(_)) => {
-                        if let Some(registration_token) = self.event_source.take() {
-                            handle.remove(registration_token);
-                        }
-
-                        // if the path has not changed or the duration has changed
-                        // and the remaining time is great than 0
-                        let timer = if let (false, Some(remaining_time)) = (
-                            path_changed,
-                            remaining_duration(
-                                new_duration,
-                                self.image_picker.image_changed_instant,
-                            ),
-                        ) {
-                            Some(Timer::from_duration(remaining_time))
+                    // There wasn't a duration before but now it has been added or it has changed,
+                    // or we just left a sync group and need to resume our own timer
+                    (Some(new_duration), _) => {
+                        if self.sync_group().is_some() {
+                            // A grouped surface doesn't run its own timer;
+                            // Wpaperd's shared group timer will advance it.
                         } else {
-                            // otherwise draw the image immediately, the next timer
-                            // will be set to the new duration
-                            Some(Timer::immediate())
-                        };
+                            if let Some(registration_token) = self.event_source.take() {
+                                handle.remove(registration_token);
+                            }
+
+                            // if the path has not changed or the duration has changed
+                            // and the remaining time is great than 0
+                            let timer = if let (false, Some(remaining_time)) = (
+                                path_changed,
+                                remaining_duration(
+                                    new_duration,
+                                    self.image_picker.image_changed_instant,
+                                ),
+                            ) {
+                                Some(Timer::from_duration(remaining_time))
+                            } else {
+                                // otherwise draw the image immediately, the next timer
+                                // will be set to the new duration
+                                Some(Timer::immediate())
+                            };
 
-                        self.add_timer(timer, handle, qh.clone());
+                            self.add_timer(timer, handle, qh.clone());
+                        }
                     }
                 }
-            } else {
-                if path_changed {
-                    self.queue_draw(qh);
-                }
+            } else if path_changed {
+                self.queue_draw(qh);
             }
         }
     }
 
+    /// Returns the sync group this surface belongs to, if any. Surfaces
+    /// sharing a sync group don't run their own rotation timer; instead
+    /// `Wpaperd` runs a single shared timer for the whole group and calls
+    /// `advance_wallpaper` on every member that has a duration configured in
+    /// the same loop iteration, so
+    /// they all start their transition on the same frame instead of
+    /// drifting apart.
+    pub fn sync_group(&self) -> Option<&str> {
+        self.wallpaper_info.group.as_deref()
+    }
+
+    /// The configured rotation duration for this surface, regardless of
+    /// whether it currently drives its own timer or is advanced by a sync
+    /// group's shared timer.
+    pub fn duration(&self) -> Option<Duration> {
+        self.wallpaper_info.duration
+    }
+
+    /// Picks the next image and queues a redraw. Called by this surface's
+    /// own timer, or by `Wpaperd` when this surface's sync group deadline
+    /// fires.
+    pub fn advance_wallpaper(&mut self, qh: &QueueHandle<Wpaperd>) {
+        self.image_picker.next_image();
+        self.queue_draw(qh);
+    }
+
     /// Add a new timer in the event_loop for the current duration
     /// Stop if there is already a timer added
     pub fn add_timer(
@@ -239,6 +366,12 @@ impl Surface {
         handle: &LoopHandle<Wpaperd>,
         qh: QueueHandle<Wpaperd>,
     ) {
+        // Surfaces in a sync group are advanced by Wpaperd's shared group
+        // timer, not by a timer of their own.
+        if self.sync_group().is_some() {
+            return;
+        }
+
         if let Some(duration) = self.wallpaper_info.duration {
             let timer = timer.unwrap_or(Timer::from_duration(duration));
             if self.event_source.is_some() {
@@ -266,8 +399,7 @@ impl Surface {
                                 TimeoutAction::ToDuration(remaining_time)
                             } else {
                                 // Change the drawn image
-                                surface.image_picker.next_image();
-                                surface.queue_draw(&qh);
+                                surface.advance_wallpaper(&qh);
                                 TimeoutAction::ToDuration(duration)
                             }
                         } else {
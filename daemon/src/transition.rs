@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+/// Which visual effect is used to cross-fade between the outgoing and the
+/// incoming wallpaper texture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransitionKind {
+    /// Cross-dissolve the two textures.
+    Fade,
+    /// Sweep a soft edge across the surface.
+    Wipe,
+    /// Reveal the incoming texture from a growing circle at the center.
+    Grow,
+}
+
+impl Default for TransitionKind {
+    fn default() -> Self {
+        Self::Fade
+    }
+}
+
+/// Easing curve applied to the normalized transition progress before it is
+/// fed into the shader as the `u_progress` uniform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Easing {
+    Linear,
+    EaseInOutCubic,
+    EaseOutQuad,
+}
+
+impl Default for Easing {
+    fn default() -> Self {
+        Self::Linear
+    }
+}
+
+impl Easing {
+    /// Maps a linear progress value in `[0, 1]` to an eased progress value,
+    /// clamping the input first since callers may pass a value that just
+    /// crossed the end of the transition.
+    pub fn apply(self, p: f32) -> f32 {
+        let p = p.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => p,
+            Easing::EaseInOutCubic => {
+                if p < 0.5 {
+                    4.0 * p * p * p
+                } else {
+                    1.0 - (-2.0 * p + 2.0).powi(3) / 2.0
+                }
+            }
+            Easing::EaseOutQuad => 1.0 - (1.0 - p) * (1.0 - p),
+        }
+    }
+}
+
+fn default_duration_ms() -> u64 {
+    600
+}
+
+/// Per-output transition configuration, set via the `transition` field of
+/// [`crate::wallpaper_info::WallpaperInfo`]. Controls how `Renderer` blends
+/// from the previously displayed texture to the newly loaded one.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Transition {
+    #[serde(default)]
+    pub kind: TransitionKind,
+    #[serde(default = "default_duration_ms")]
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+impl Default for Transition {
+    fn default() -> Self {
+        Self {
+            kind: TransitionKind::default(),
+            duration_ms: default_duration_ms(),
+            easing: Easing::default(),
+        }
+    }
+}
+
+impl Transition {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// Turns an elapsed `Duration` since the transition started into the
+    /// eased progress value the shader should use, `1.0` meaning "fully
+    /// switched over to the incoming texture".
+    pub fn progress(&self, elapsed: Duration) -> f32 {
+        let duration = self.duration().as_secs_f32();
+        let p = if duration == 0.0 {
+            1.0
+        } else {
+            elapsed.as_secs_f32() / duration
+        };
+        self.easing.apply(p)
+    }
+}
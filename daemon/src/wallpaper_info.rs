@@ -0,0 +1,51 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::transition::Transition;
+
+/// How the loaded image is fit into the surface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackgroundMode {
+    Stretch,
+    Center,
+    Fit,
+    FitBorderColor,
+    Tile,
+}
+
+impl Default for BackgroundMode {
+    fn default() -> Self {
+        Self::Stretch
+    }
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let seconds: Option<u64> = Deserialize::deserialize(deserializer)?;
+    Ok(seconds.map(Duration::from_secs))
+}
+
+/// Per-output wallpaper configuration, as read from `wpaperd.toml`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WallpaperInfo {
+    /// A single image, or a directory to pick images from.
+    pub path: PathBuf,
+    #[serde(default)]
+    pub mode: BackgroundMode,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub duration: Option<Duration>,
+    #[serde(default)]
+    pub apply_shadow: Option<bool>,
+    #[serde(default)]
+    pub transition: Transition,
+    /// Name of a sync group. Outputs sharing the same group name advance
+    /// their wallpaper rotation together instead of independently; see
+    /// `Surface::sync_group`.
+    #[serde(default)]
+    pub group: Option<String>,
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use smithay_client_toolkit::reexports::calloop::timer::{TimeoutAction, Timer};
+use smithay_client_toolkit::reexports::calloop::{LoopHandle, RegistrationToken};
+use smithay_client_toolkit::reexports::client::QueueHandle;
+
+use crate::surface::Surface;
+use crate::wallpaper_info::WallpaperInfo;
+
+/// Owns every output's `Surface` and the event loop they're driven from.
+///
+/// Surfaces in the same sync group don't run their own rotation timer (see
+/// `Surface::add_timer`); instead `Wpaperd` runs one shared timer per group
+/// and advances every member surface that has a duration configured in the
+/// same loop iteration, so they
+/// start their transition on the same frame instead of drifting apart.
+pub struct Wpaperd {
+    pub surfaces: Vec<Surface>,
+    pub loop_handle: LoopHandle<Wpaperd>,
+    /// One timer per active sync group, keyed by group name, alongside the
+    /// duration it was started with so `sync_group_timers` can tell a
+    /// membership change that alters the shortest duration apart from one
+    /// that doesn't.
+    group_timers: HashMap<String, (Duration, RegistrationToken)>,
+}
+
+impl Wpaperd {
+    pub fn new(loop_handle: LoopHandle<Wpaperd>) -> Self {
+        Self {
+            surfaces: Vec::new(),
+            loop_handle,
+            group_timers: HashMap::new(),
+        }
+    }
+
+    pub fn surface_from_name(&mut self, name: &str) -> Option<&mut Surface> {
+        self.surfaces.iter_mut().find(|surface| surface.name() == name)
+    }
+
+    /// Adds a newly created surface (initial output discovery, or hotplug)
+    /// and reconciles group timers, since it may be the first (or another)
+    /// member of a sync group.
+    pub fn add_surface(&mut self, surface: Surface, qh: &QueueHandle<Wpaperd>) {
+        self.surfaces.push(surface);
+        self.sync_group_timers(qh);
+    }
+
+    /// Removes the surface for `name` (e.g. its output was unplugged) and
+    /// reconciles group timers, since its departure may leave a group with
+    /// no members left.
+    pub fn remove_surface(&mut self, name: &str, qh: &QueueHandle<Wpaperd>) {
+        self.surfaces.retain(|surface| surface.name() != name);
+        self.sync_group_timers(qh);
+    }
+
+    /// Updates `name`'s wallpaper info (e.g. after a config reload) and
+    /// reconciles group timers afterwards, since joining/leaving a group or
+    /// changing `duration` can change which group timer(s) should run.
+    pub fn update_wallpaper_info(
+        &mut self,
+        name: &str,
+        qh: &QueueHandle<Wpaperd>,
+        wallpaper_info: Arc<WallpaperInfo>,
+    ) {
+        let handle = self.loop_handle.clone();
+        if let Some(surface) = self.surface_from_name(name) {
+            surface.update_wallpaper_info(&handle, qh, wallpaper_info);
+        }
+        self.sync_group_timers(qh);
+    }
+
+    /// Reconciles the set of running group timers against the sync groups
+    /// currently in use by `self.surfaces`. Call this whenever surface
+    /// membership or group assignment could have changed: config reload,
+    /// hotplug (an output, and thus its surface, appearing or disappearing),
+    /// or a surface joining/leaving a group.
+    ///
+    /// Groups with no more members (or whose last member left) have their
+    /// timer removed; groups that appeared, or whose shortest configured
+    /// duration changed, get a (re)started timer using the shortest
+    /// duration configured among their current members.
+    pub fn sync_group_timers(&mut self, qh: &QueueHandle<Wpaperd>) {
+        let mut durations: HashMap<String, Duration> = HashMap::new();
+        for surface in &self.surfaces {
+            let (Some(group), Some(duration)) = (surface.sync_group(), surface.duration()) else {
+                continue;
+            };
+            durations
+                .entry(group.to_string())
+                .and_modify(|shortest| *shortest = (*shortest).min(duration))
+                .or_insert(duration);
+        }
+
+        let stale_groups: Vec<String> = self
+            .group_timers
+            .keys()
+            .filter(|group| !durations.contains_key(*group))
+            .cloned()
+            .collect();
+        for group in stale_groups {
+            if let Some((_, token)) = self.group_timers.remove(&group) {
+                self.loop_handle.remove(token);
+            }
+        }
+
+        for (group, duration) in durations {
+            if let Some((running_duration, _)) = self.group_timers.get(&group) {
+                if *running_duration == duration {
+                    continue;
+                }
+                // The shortest duration among the group's members changed
+                // (a member joined/left, or had its duration edited);
+                // restart the timer so it reflects the new duration
+                // instead of silently keeping firing on the stale one.
+                if let Some((_, token)) = self.group_timers.remove(&group) {
+                    self.loop_handle.remove(token);
+                }
+            }
+            let token = self.start_group_timer(group.clone(), duration, qh.clone());
+            self.group_timers.insert(group, (duration, token));
+        }
+    }
+
+    /// Starts a single shared timer for `group`, firing every `duration` and
+    /// advancing every surface currently in that group that has a duration
+    /// configured, all in the same loop iteration.
+    fn start_group_timer(
+        &self,
+        group: String,
+        duration: Duration,
+        qh: QueueHandle<Wpaperd>,
+    ) -> RegistrationToken {
+        self.loop_handle
+            .insert_source(
+                Timer::from_duration(duration),
+                move |_deadline, _: &mut (), wpaperd: &mut Wpaperd| {
+                    for surface in &mut wpaperd.surfaces {
+                        // A member without its own duration doesn't rotate
+                        // at all; it only shares the group name so it can
+                        // sit alongside rotating members without running a
+                        // timer of its own.
+                        if surface.sync_group() == Some(group.as_str())
+                            && surface.duration().is_some()
+                        {
+                            surface.advance_wallpaper(&qh);
+                        }
+                    }
+                    TimeoutAction::ToDuration(duration)
+                },
+            )
+            .expect("Failed to insert event source!")
+    }
+}